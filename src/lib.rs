@@ -1,20 +1,55 @@
 use std::any::Any;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
 use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
+/// The slot is free: nothing currently borrows it.
+const FREE: isize = 0;
+/// The slot is exclusively (mutably) borrowed.
+const EXCLUSIVE: isize = -1;
+/// The slot's value was removed from the store.
+const REMOVED: isize = isize::MIN;
+
+/// An error occurring while trying to fallibly access a value in a `Store`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// The token's value was already removed from the store
+    Removed,
+    /// The value is already borrowed in a conflicting way (e.g. a second
+    /// exclusive borrow, or via `StoreProxy::with_value`)
+    AlreadyBorrowed,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccessError::Removed => write!(f, "the value was already removed from the store"),
+            AccessError::AlreadyBorrowed => write!(f, "the value is already borrowed"),
+        }
+    }
+}
+
+impl Error for AccessError {}
+
 /// A token store
 ///
 /// This struct allows you to store various values in a store
 /// and access them back using the provided tokens.
 pub struct Store {
-    values: Vec<Option<(Box<Any>, Rc<Cell<bool>>)>>,
+    values: Vec<Option<(Box<Any>, Rc<Cell<isize>>)>>,
+    /// Ids whose removal was requested via `remove_deferred` while they were
+    /// borrowed; reclaimed by `flush`/`checkpoint`.
+    pending: RefCell<Vec<usize>>,
 }
 
 /// A token for accessing the store contents
 pub struct Token<V> {
     id: usize,
-    live: Rc<Cell<bool>>,
+    live: Rc<Cell<isize>>,
     _type: PhantomData<V>,
 }
 
@@ -31,7 +66,10 @@ impl<V> Clone for Token<V> {
 impl Store {
     /// Create a new store
     pub fn new() -> Store {
-        Store { values: Vec::new() }
+        Store {
+            values: Vec::new(),
+            pending: RefCell::new(Vec::new()),
+        }
     }
 
     /// Insert a new value in this store
@@ -39,8 +77,11 @@ impl Store {
     /// Returns a clonable token that you can later use to access this
     /// value.
     pub fn insert<V: Any + 'static>(&mut self, value: V) -> Token<V> {
+        // Reclaim any pending removals so their slots are available for
+        // reuse below.
+        self.flush();
         let boxed = Box::new(value) as Box<Any>;
-        let live = Rc::new(Cell::new(true));
+        let live = Rc::new(Cell::new(FREE));
         {
             // artificial scope to make the borrow checker happy
             let empty_slot = self.values
@@ -66,28 +107,62 @@ impl Store {
 
     /// Access value previously inserted in this store
     ///
-    /// Panics if the provided token corresponds to a value that was removed.
+    /// Panics if the provided token corresponds to a value that was removed,
+    /// or is currently exclusively borrowed via `borrow_mut`.
     pub fn get<V: Any + 'static>(&self, token: &Token<V>) -> &V {
-        if !token.live.get() {
-            panic!("Attempted to access a state value that was already removed!");
+        self.try_get(token).unwrap()
+    }
+
+    /// Access value previously inserted in this store
+    ///
+    /// Returns `Err(AccessError::Removed)` if the provided token corresponds to
+    /// a value that was removed, or `Err(AccessError::AlreadyBorrowed)` if it
+    /// is currently exclusively borrowed via `borrow_mut`, instead of
+    /// panicking.
+    pub fn try_get<V: Any + 'static>(&self, token: &Token<V>) -> Result<&V, AccessError> {
+        let state = token.live.get();
+        if state == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
+        }
+        if state == EXCLUSIVE {
+            return Err(AccessError::AlreadyBorrowed);
         }
-        self.values[token.id]
+        Ok(self.values[token.id]
             .as_ref()
             .and_then(|t| t.0.downcast_ref::<V>())
-            .unwrap()
+            .unwrap())
     }
 
     /// Mutably access value previously inserted in this store
     ///
-    /// Panics if the provided token corresponds to a value that was removed.
+    /// Panics if the provided token corresponds to a value that was removed,
+    /// or is currently borrowed (shared or exclusive) via
+    /// `borrow`/`borrow_mut`.
     pub fn get_mut<V: Any + 'static>(&mut self, token: &Token<V>) -> &mut V {
-        if !token.live.get() {
-            panic!("Attempted to access a state value that was already removed!");
+        self.try_get_mut(token).unwrap()
+    }
+
+    /// Mutably access value previously inserted in this store
+    ///
+    /// Returns `Err(AccessError::Removed)` if the provided token corresponds to
+    /// a value that was removed, or `Err(AccessError::AlreadyBorrowed)` if it
+    /// is currently borrowed (shared or exclusive) via `borrow`/`borrow_mut`,
+    /// instead of panicking.
+    pub fn try_get_mut<V: Any + 'static>(
+        &mut self,
+        token: &Token<V>,
+    ) -> Result<&mut V, AccessError> {
+        let state = token.live.get();
+        if state == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
         }
-        self.values[token.id]
+        if state != FREE {
+            return Err(AccessError::AlreadyBorrowed);
+        }
+        Ok(self.values[token.id]
             .as_mut()
             .and_then(|t| t.0.downcast_mut::<V>())
-            .unwrap()
+            .unwrap())
     }
 
     /// Remove a value previously inserted in this store
@@ -95,12 +170,194 @@ impl Store {
     /// Panics if the provided token corresponds to a value that was already
     /// removed.
     pub fn remove<V: Any + 'static>(&mut self, token: Token<V>) -> V {
-        if !token.live.get() {
-            panic!("Attempted to remove a state value that was already removed!");
+        self.try_remove(token).unwrap()
+    }
+
+    /// Remove a value previously inserted in this store
+    ///
+    /// Returns `Err(AccessError::Removed)` if the provided token corresponds to
+    /// a value that was already removed, instead of panicking.
+    pub fn try_remove<V: Any + 'static>(&mut self, token: Token<V>) -> Result<V, AccessError> {
+        if token.live.get() == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
         }
         let (boxed, live) = self.values[token.id].take().unwrap();
-        live.set(false);
-        *boxed.downcast().unwrap()
+        live.set(REMOVED);
+        Ok(*boxed.downcast().unwrap())
+    }
+
+    /// Remove a value, deferring the actual drop if it is currently borrowed
+    ///
+    /// This only needs `&self`, so it can be called while a `Ref`/`RefMut`
+    /// guard for another token is still outstanding. If nothing currently
+    /// borrows this token, it is freed immediately, just like `remove`; only
+    /// a token with a live `Ref`/`RefMut` is held back for a later
+    /// `flush`/`checkpoint` to reclaim.
+    ///
+    /// Panics if the token was already removed.
+    pub fn remove_deferred<V: Any + 'static>(&self, token: Token<V>) {
+        self.try_remove_deferred(token).unwrap()
+    }
+
+    /// Remove a value, deferring the actual drop if it is currently borrowed
+    ///
+    /// Returns `Err(AccessError::Removed)` if the token was already removed,
+    /// instead of panicking.
+    pub fn try_remove_deferred<V: Any + 'static>(&self, token: Token<V>) -> Result<(), AccessError> {
+        let state = token.live.get();
+        if state == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
+        }
+        if state == FREE {
+            // SAFETY: `state == FREE` means no `Ref`/`RefMut` holds a pointer
+            // into this slot, so taking it through a raw pointer derived
+            // from `&self` aliases no live reference.
+            let slot = &self.values[token.id] as *const _
+                as *mut Option<(Box<Any>, Rc<Cell<isize>>)>;
+            if let Some((_, live)) = unsafe { (*slot).take() } {
+                live.set(REMOVED);
+            }
+        } else {
+            self.pending.borrow_mut().push(token.id);
+        }
+        Ok(())
+    }
+
+    /// Reclaim any slots whose removal was deferred by `remove_deferred`
+    ///
+    /// Returns the ids that were freed by this call; any token still
+    /// referring to them already reports `AccessError::Removed` regardless of
+    /// whether `flush` has run yet.
+    ///
+    /// `&mut self` guarantees no `Ref`/`RefMut` guard is outstanding anywhere
+    /// in the store, so every pending id is necessarily free to reclaim.
+    pub fn flush(&mut self) -> Vec<usize> {
+        let pending = mem::take(self.pending.get_mut());
+        for &id in &pending {
+            if let Some((_, live)) = self.values[id].take() {
+                live.set(REMOVED);
+            }
+        }
+        pending
+    }
+
+    /// Alias for `flush`
+    pub fn checkpoint(&mut self) -> Vec<usize> {
+        self.flush()
+    }
+
+    /// Immutably borrow a value previously inserted in this store
+    ///
+    /// Unlike `get`, the returned guard records a shared borrow in the slot's
+    /// state until it is dropped, so `borrow_mut` on the same token fails
+    /// while it is held; it can be held alongside other independent guards
+    /// without nesting.
+    ///
+    /// Panics if the provided token corresponds to a value that was removed,
+    /// or is already exclusively borrowed.
+    pub fn borrow<V: Any + 'static>(&self, token: &Token<V>) -> Ref<'_, V> {
+        self.try_borrow(token).unwrap()
+    }
+
+    /// Immutably borrow a value previously inserted in this store
+    ///
+    /// Returns `Err(AccessError::Removed)` if the value was removed, or
+    /// `Err(AccessError::AlreadyBorrowed)` if it is exclusively borrowed,
+    /// instead of panicking.
+    pub fn try_borrow<V: Any + 'static>(&self, token: &Token<V>) -> Result<Ref<'_, V>, AccessError> {
+        let state = token.live.get();
+        if state == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
+        }
+        if state == EXCLUSIVE {
+            return Err(AccessError::AlreadyBorrowed);
+        }
+        token.live.set(state + 1);
+        let value_ptr = self.values[token.id]
+            .as_ref()
+            .and_then(|t| t.0.downcast_ref::<V>())
+            .unwrap() as *const V;
+        Ok(Ref {
+            value: value_ptr,
+            flag: token.live.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Mutably borrow a value previously inserted in this store
+    ///
+    /// Unlike `get_mut`, the returned guard can be held alongside other
+    /// independent guards for disjoint tokens without nesting.
+    ///
+    /// Panics if the provided token corresponds to a value that was removed,
+    /// or is already borrowed.
+    pub fn borrow_mut<V: Any + 'static>(&self, token: &Token<V>) -> RefMut<'_, V> {
+        self.try_borrow_mut(token).unwrap()
+    }
+
+    /// Mutably borrow a value previously inserted in this store
+    ///
+    /// Returns `Err(AccessError::Removed)` if the value was removed, or
+    /// `Err(AccessError::AlreadyBorrowed)` if it is already borrowed (shared
+    /// or exclusive), instead of panicking.
+    pub fn try_borrow_mut<V: Any + 'static>(
+        &self,
+        token: &Token<V>,
+    ) -> Result<RefMut<'_, V>, AccessError> {
+        let state = token.live.get();
+        if state == REMOVED || self.pending.borrow().contains(&token.id) {
+            return Err(AccessError::Removed);
+        }
+        if state != FREE {
+            return Err(AccessError::AlreadyBorrowed);
+        }
+        token.live.set(EXCLUSIVE);
+        let value_ptr = self.values[token.id]
+            .as_ref()
+            .and_then(|t| t.0.downcast_ref::<V>())
+            .unwrap() as *const V as *mut V;
+        Ok(RefMut {
+            value: value_ptr,
+            flag: token.live.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Mutably borrow several disjoint tokens at once
+    ///
+    /// `tokens` is a tuple of `&Token<_>` references (possibly naming
+    /// different stored types), and the matching tuple of `&mut` references
+    /// is returned in one call, without the nesting `with_value` would
+    /// require for the same result.
+    ///
+    /// Panics if two of the tokens alias the same slot, or if any of them
+    /// corresponds to a value that was removed.
+    pub fn get_disjoint_mut<'a, T: DisjointTokens<'a>>(&'a mut self, tokens: T) -> T::Output {
+        self.try_get_disjoint_mut(tokens).unwrap()
+    }
+
+    /// Mutably borrow several disjoint tokens at once
+    ///
+    /// Returns `Err(AccessError::AlreadyBorrowed)` if two of the tokens alias
+    /// the same slot, or `Err(AccessError::Removed)` if any of them was
+    /// removed, instead of panicking.
+    pub fn try_get_disjoint_mut<'a, T: DisjointTokens<'a>>(
+        &'a mut self,
+        tokens: T,
+    ) -> Result<T::Output, AccessError> {
+        let ids = tokens.ids();
+        for (i, &id) in ids.iter().enumerate() {
+            if ids[..i].contains(&id) {
+                return Err(AccessError::AlreadyBorrowed);
+            }
+        }
+        if ids.iter().any(|id| self.pending.borrow().contains(id)) || !tokens.all_live() {
+            return Err(AccessError::Removed);
+        }
+        // SAFETY: the ids were just checked pairwise distinct, and each token
+        // was checked live, so `fetch` hands out non-aliasing `&mut`s into
+        // live slots.
+        Ok(unsafe { tokens.fetch(self) })
     }
 
     pub fn with_value<V: Any + 'static, T, F>(&mut self, token: &Token<V>, f: F) -> T
@@ -138,10 +395,19 @@ impl<'store> StoreProxy<'store> {
     /// Panics if the provided token corresponds to a value that was removed, or
     /// if this value is already borrowed.
     pub fn get<V: Any + 'static>(&self, token: &Token<V>) -> &V {
+        self.try_get(token).unwrap()
+    }
+
+    /// Access value previously inserted in the proxified store
+    ///
+    /// Returns `Err(AccessError::AlreadyBorrowed)` if the value is already
+    /// borrowed, or `Err(AccessError::Removed)` if it was removed, instead of
+    /// panicking.
+    pub fn try_get<V: Any + 'static>(&self, token: &Token<V>) -> Result<&V, AccessError> {
         if self.borrowed.contains(&token.id) {
-            panic!("Attempted to borrow twice the same value from the Store!");
+            return Err(AccessError::AlreadyBorrowed);
         }
-        self.store.get(token)
+        self.store.try_get(token)
     }
 
     /// Mutably access value previously inserted in the proxified store
@@ -149,10 +415,22 @@ impl<'store> StoreProxy<'store> {
     /// Panics if the provided token corresponds to a value that was removed, or
     /// if this value is already borrowed.
     pub fn get_mut<V: Any + 'static>(&mut self, token: &Token<V>) -> &mut V {
+        self.try_get_mut(token).unwrap()
+    }
+
+    /// Mutably access value previously inserted in the proxified store
+    ///
+    /// Returns `Err(AccessError::AlreadyBorrowed)` if the value is already
+    /// borrowed, or `Err(AccessError::Removed)` if it was removed, instead of
+    /// panicking.
+    pub fn try_get_mut<V: Any + 'static>(
+        &mut self,
+        token: &Token<V>,
+    ) -> Result<&mut V, AccessError> {
         if self.borrowed.contains(&token.id) {
-            panic!("Attempted to borrow twice the same value from the Store!");
+            return Err(AccessError::AlreadyBorrowed);
         }
-        self.store.get_mut(token)
+        self.store.try_get_mut(token)
     }
 
     /// Remove a value previously inserted in the proxified store
@@ -160,10 +438,19 @@ impl<'store> StoreProxy<'store> {
     /// Panics if the provided token corresponds to a value that was already
     /// removed.
     pub fn remove<V: Any + 'static>(&mut self, token: Token<V>) -> V {
+        self.try_remove(token).unwrap()
+    }
+
+    /// Remove a value previously inserted in the proxified store
+    ///
+    /// Returns `Err(AccessError::AlreadyBorrowed)` if the value is currently
+    /// borrowed, or `Err(AccessError::Removed)` if it was already removed,
+    /// instead of panicking.
+    pub fn try_remove<V: Any + 'static>(&mut self, token: Token<V>) -> Result<V, AccessError> {
         if self.borrowed.contains(&token.id) {
-            panic!("Attempted to remove a value from the Store while it was borrowed!");
+            return Err(AccessError::AlreadyBorrowed);
         }
-        self.store.remove(token)
+        self.store.try_remove(token)
     }
 
     pub fn with_value<V: Any + 'static, T, F>(&mut self, token: &Token<V>, f: F) -> T
@@ -187,6 +474,199 @@ impl<'store> StoreProxy<'store> {
     }
 }
 
+/// A guard holding a shared borrow of a value obtained through `Store::borrow`
+///
+/// Dropping the guard releases the borrow. The pointer representation lets
+/// `map`/`try_map` project it onto a subcomponent of `V` while keeping the
+/// original borrow held.
+pub struct Ref<'a, V: ?Sized + 'a> {
+    value: *const V,
+    flag: Rc<Cell<isize>>,
+    _marker: PhantomData<&'a V>,
+}
+
+impl<'a, V: ?Sized> Deref for Ref<'a, V> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, V: ?Sized> Drop for Ref<'a, V> {
+    fn drop(&mut self) {
+        let state = self.flag.get();
+        self.flag.set(state - 1);
+    }
+}
+
+impl<'a, V> Ref<'a, V> {
+    /// Project a guard onto a subcomponent of its value
+    ///
+    /// The original slot's borrow count stays held until the returned guard
+    /// is dropped in turn.
+    pub fn map<U: ?Sized, F>(orig: Ref<'a, V>, f: F) -> Ref<'a, U>
+    where
+        F: FnOnce(&V) -> &U,
+    {
+        let value = f(unsafe { &*orig.value }) as *const U;
+        let flag = orig.flag.clone();
+        mem::forget(orig);
+        Ref {
+            value: value,
+            flag: flag,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempt to project a guard onto a subcomponent of its value
+    ///
+    /// On failure, the original guard is handed back unchanged so the caller
+    /// keeps holding its borrow.
+    pub fn try_map<U: ?Sized, F>(orig: Ref<'a, V>, f: F) -> Result<Ref<'a, U>, Ref<'a, V>>
+    where
+        F: FnOnce(&V) -> Option<&U>,
+    {
+        match f(unsafe { &*orig.value }) {
+            Some(u) => {
+                let value = u as *const U;
+                let flag = orig.flag.clone();
+                mem::forget(orig);
+                Ok(Ref {
+                    value: value,
+                    flag: flag,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+/// A guard holding an exclusive borrow of a value obtained through
+/// `Store::borrow_mut`
+///
+/// Dropping the guard releases the borrow. The pointer representation lets
+/// `map`/`try_map` project it onto a subcomponent of `V` while keeping the
+/// original borrow held.
+pub struct RefMut<'a, V: ?Sized + 'a> {
+    value: *mut V,
+    flag: Rc<Cell<isize>>,
+    _marker: PhantomData<&'a mut V>,
+}
+
+impl<'a, V: ?Sized> Deref for RefMut<'a, V> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, V: ?Sized> DerefMut for RefMut<'a, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<'a, V: ?Sized> Drop for RefMut<'a, V> {
+    fn drop(&mut self) {
+        self.flag.set(FREE);
+    }
+}
+
+impl<'a, V> RefMut<'a, V> {
+    /// Project a guard onto a subcomponent of its value
+    ///
+    /// The original slot's borrow stays held until the returned guard is
+    /// dropped in turn.
+    pub fn map<U: ?Sized, F>(orig: RefMut<'a, V>, f: F) -> RefMut<'a, U>
+    where
+        F: FnOnce(&mut V) -> &mut U,
+    {
+        let value = f(unsafe { &mut *orig.value }) as *mut U;
+        let flag = orig.flag.clone();
+        mem::forget(orig);
+        RefMut {
+            value: value,
+            flag: flag,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempt to project a guard onto a subcomponent of its value
+    ///
+    /// On failure, the original guard is handed back unchanged so the caller
+    /// keeps holding its borrow.
+    pub fn try_map<U: ?Sized, F>(orig: RefMut<'a, V>, f: F) -> Result<RefMut<'a, U>, RefMut<'a, V>>
+    where
+        F: FnOnce(&mut V) -> Option<&mut U>,
+    {
+        let mapped = f(unsafe { &mut *orig.value }).map(|u| u as *mut U);
+        match mapped {
+            Some(value) => {
+                let flag = orig.flag.clone();
+                mem::forget(orig);
+                Ok(RefMut {
+                    value: value,
+                    flag: flag,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
+}
+
+/// A tuple of token references whose values can be fetched as mutually
+/// disjoint `&mut` references in a single call to `Store::get_disjoint_mut`
+///
+/// Implemented for tuples of 2 to 4 `&Token<_>`, each possibly naming a
+/// different stored type.
+pub trait DisjointTokens<'a> {
+    /// The tuple of `&mut` references handed back on success
+    type Output;
+
+    #[doc(hidden)]
+    fn ids(&self) -> Vec<usize>;
+    #[doc(hidden)]
+    fn all_live(&self) -> bool;
+    #[doc(hidden)]
+    unsafe fn fetch(self, store: &'a mut Store) -> Self::Output;
+}
+
+macro_rules! impl_disjoint_tokens {
+    ($($T:ident : $t:ident),+) => {
+        impl<'a, $($T: Any + 'static),+> DisjointTokens<'a> for ($(&'a Token<$T>,)+) {
+            type Output = ($(&'a mut $T,)+);
+
+            fn ids(&self) -> Vec<usize> {
+                let &($(ref $t,)+) = self;
+                vec![$($t.id),+]
+            }
+
+            fn all_live(&self) -> bool {
+                let &($(ref $t,)+) = self;
+                $($t.live.get() != REMOVED)&&+
+            }
+
+            unsafe fn fetch(self, store: &'a mut Store) -> Self::Output {
+                let ($($t,)+) = self;
+                ($(
+                    unsafe {
+                        &mut *(store.values[$t.id]
+                            .as_mut()
+                            .and_then(|v| v.0.downcast_mut::<$T>())
+                            .unwrap() as *mut $T)
+                    },
+                )+)
+            }
+        }
+    };
+}
+
+impl_disjoint_tokens!(A: a, B: b);
+impl_disjoint_tokens!(A: a, B: b, C: c);
+impl_disjoint_tokens!(A: a, B: b, C: c, D: d);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -301,6 +781,28 @@ mod tests {
         });
     }
 
+    #[test]
+    fn try_get_removed() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let token2 = token.clone();
+        store.remove(token2);
+        assert_eq!(store.try_get(&token), Err(AccessError::Removed));
+    }
+
+    #[test]
+    fn try_get_already_borrowed() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        store.with_value(&token, |proxy, _| {
+            assert_eq!(proxy.try_get(&token), Err(AccessError::AlreadyBorrowed));
+            assert_eq!(
+                proxy.try_get_mut(&token),
+                Err(AccessError::AlreadyBorrowed)
+            );
+        });
+    }
+
     #[test]
     #[should_panic]
     fn no_alias_remove_and_with_value() {
@@ -311,4 +813,207 @@ mod tests {
         });
     }
 
+    #[test]
+    fn borrow_shared() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let a = store.borrow(&token);
+        let b = store.borrow(&token);
+        assert_eq!(*a, 42);
+        assert_eq!(*b, 42);
+    }
+
+    #[test]
+    fn borrow_mut_disjoint_tokens() {
+        let mut store = Store::new();
+        let token1 = store.insert(1);
+        let token2 = store.insert(2);
+        let mut a = store.borrow_mut(&token1);
+        let mut b = store.borrow_mut(&token2);
+        *a += 10;
+        *b += 20;
+        assert_eq!(*a, 11);
+        assert_eq!(*b, 22);
+    }
+
+    #[test]
+    fn try_borrow_mut_while_shared() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let _a = store.borrow(&token);
+        let result = store.try_borrow_mut(&token);
+        match result {
+            Err(AccessError::AlreadyBorrowed) => {}
+            _ => panic!("expected AlreadyBorrowed"),
+        }
+    }
+
+    #[test]
+    fn try_borrow_while_exclusive() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let _a = store.borrow_mut(&token);
+        let result = store.try_borrow(&token);
+        match result {
+            Err(AccessError::AlreadyBorrowed) => {}
+            _ => panic!("expected AlreadyBorrowed"),
+        }
+    }
+
+    #[test]
+    fn borrow_released_on_drop() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        {
+            let _a = store.borrow_mut(&token);
+        }
+        assert_eq!(*store.borrow(&token), 42);
+    }
+
+    #[test]
+    fn try_get_while_exclusively_borrowed() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let mut guard = store.borrow_mut(&token);
+        *guard = 100;
+        assert_eq!(store.try_get(&token), Err(AccessError::AlreadyBorrowed));
+        assert_eq!(*guard, 100);
+    }
+
+    #[test]
+    fn ref_map_projects_subcomponent() {
+        let mut store = Store::new();
+        let token = store.insert(vec![1, 2, 3]);
+        let r = store.borrow(&token);
+        let first = Ref::map(r, |v| &v[0]);
+        assert_eq!(*first, 1);
+    }
+
+    #[test]
+    fn ref_mut_map_holds_borrow_until_dropped() {
+        let mut store = Store::new();
+        let token = store.insert(vec![1, 2, 3]);
+        {
+            let m = store.borrow_mut(&token);
+            let mut first = RefMut::map(m, |v| &mut v[0]);
+            *first = 42;
+            assert_eq!(store.try_get(&token), Err(AccessError::AlreadyBorrowed));
+        }
+        assert_eq!(store.get(&token)[0], 42);
+    }
+
+    #[test]
+    fn ref_try_map_failure_returns_original() {
+        let mut store = Store::new();
+        let token = store.insert(vec![1, 2, 3]);
+        let r = store.borrow(&token);
+        let result = Ref::try_map(r, |v: &Vec<i32>| v.get(10));
+        match result {
+            Ok(_) => panic!("expected the projection to fail"),
+            Err(orig) => assert_eq!(*orig, vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn get_disjoint_mut_two_tokens() {
+        let mut store = Store::new();
+        let token1 = store.insert(1);
+        let token2 = store.insert("hello".to_owned());
+        let (a, b) = store.get_disjoint_mut((&token1, &token2));
+        *a += 10;
+        b.push_str(" world");
+        assert_eq!(*store.get(&token1), 11);
+        assert_eq!(store.get(&token2), "hello world");
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_disjoint_mut_aliased_tokens_panics() {
+        let mut store = Store::new();
+        let token = store.insert(1);
+        let token2 = token.clone();
+        store.get_disjoint_mut((&token, &token2));
+    }
+
+    #[test]
+    fn try_get_disjoint_mut_removed_token() {
+        let mut store = Store::new();
+        let token1 = store.insert(1);
+        let token2 = store.insert(2);
+        let token1_clone = token1.clone();
+        store.remove(token1_clone);
+        let result = store.try_get_disjoint_mut((&token1, &token2));
+        match result {
+            Err(AccessError::Removed) => {}
+            _ => panic!("expected Removed"),
+        }
+    }
+
+    #[test]
+    fn remove_deferred_marks_removed_right_away() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let token_clone = token.clone();
+        store.remove_deferred(token_clone);
+        let result = store.try_get(&token);
+        match result {
+            Err(AccessError::Removed) => {}
+            _ => panic!("expected Removed"),
+        }
+        // nothing was borrowing the slot, so it was freed immediately;
+        // there is nothing left for flush to do.
+        assert_eq!(store.flush(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn remove_deferred_waits_for_outstanding_borrow() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let token_clone = token.clone();
+        let guard = store.borrow(&token);
+        store.remove_deferred(token_clone);
+
+        // the value is still immediately reported as removed ...
+        let result = store.try_get(&token);
+        match result {
+            Err(AccessError::Removed) => {}
+            _ => panic!("expected Removed"),
+        }
+        // ... but the backing value is still alive through the guard.
+        assert_eq!(*guard, 42);
+        drop(guard);
+
+        // once the last guard is gone, flush can reclaim the slot.
+        assert_eq!(store.flush(), vec![token.id]);
+    }
+
+    #[test]
+    fn remove_deferred_drops_value_immediately_when_unborrowed() {
+        struct DropFlag(Rc<Cell<bool>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let mut store = Store::new();
+        let dropped = Rc::new(Cell::new(false));
+        let token = store.insert(DropFlag(dropped.clone()));
+        store.remove_deferred(token);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn insert_reuses_slot_freed_by_flush() {
+        let mut store = Store::new();
+        let token = store.insert(42);
+        let guard = store.borrow(&token);
+        let token_clone = token.clone();
+        store.remove_deferred(token_clone);
+        drop(guard);
+
+        let new_token = store.insert("I like trains");
+        assert_eq!(store.values.len(), 1);
+        assert_eq!(*store.get(&new_token), "I like trains");
+    }
 }